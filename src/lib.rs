@@ -1,79 +1,280 @@
 #![feature(ptr_internals)]
 
-use std::alloc::{alloc, dealloc, realloc, Layout};
+use std::alloc::{alloc, dealloc, handle_alloc_error, realloc, Layout};
+use std::iter::FromIterator;
+use std::marker::PhantomData;
 use std::mem;
 use std::ops::{Deref, DerefMut};
-use std::process;
 use std::ptr::{self, Unique};
 
+/// Builds a [`Vec`] the same way the standard `vec!` macro builds a
+/// `std::vec::Vec`: `vec_impl![1, 2, 3]` or `vec_impl![elem; n]`.
+#[macro_export]
+macro_rules! vec_impl {
+    () => {
+        $crate::Vec::new()
+    };
+    ($elem:expr; $n:expr) => {{
+        let n = $n;
+        let elem = $elem;
+        let mut v = $crate::Vec::with_capacity(n);
+
+        if n > 0 {
+            for _ in 1..n {
+                v.push(::std::clone::Clone::clone(&elem));
+            }
+
+            v.push(elem);
+        }
+
+        v
+    }};
+    ($($x:expr),+ $(,)?) => {{
+        let elems = [$($x),+];
+        let mut v = $crate::Vec::with_capacity(elems.len());
+
+        for elem in elems {
+            v.push(elem);
+        }
+
+        v
+    }};
+}
+
 pub struct Vec<T> {
-    ptr: Unique<T>,
-    cap: usize,
+    buf: RawVec<T>,
     len: usize,
 }
 
-struct IntoIter<T> {
-    buf: Unique<T>,
-    cap: usize,
+pub struct IntoIter<T> {
+    _buf: RawVec<T>,
+    iter: RawValIter<T>,
+}
+
+pub struct Drain<'a, T: 'a> {
+    vec: PhantomData<&'a mut Vec<T>>,
+    iter: RawValIter<T>,
+}
+
+/// The start/end pointer walk shared by `IntoIter` and `Drain`. It owns no
+/// allocation itself (`IntoIter` keeps the buffer in its own `RawVec`, and
+/// `Drain` borrows the vector's), so this holds no more than is needed to
+/// produce items.
+struct RawValIter<T> {
     start: *const T,
     end: *const T,
 }
 
-impl<T> Vec<T> {
-    pub fn new() -> Self {
-        assert_ne!(mem::size_of::<T>(), 0, "I'm not ready to handle ZSTs ):");
+impl<T> RawValIter<T> {
+    /// Caller must ensure the `RawValIter` is not outlived by the slice it
+    /// was built from.
+    unsafe fn new(slice: &[T]) -> Self {
+        RawValIter {
+            start: slice.as_ptr(),
+            end: if mem::size_of::<T>() == 0 {
+                ((slice.as_ptr() as usize) + slice.len()) as *const _
+            } else if slice.is_empty() {
+                slice.as_ptr()
+            } else {
+                slice.as_ptr().offset(slice.len() as isize)
+            },
+        }
+    }
+}
 
-        Vec {
+impl<T> Iterator for RawValIter<T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        if self.start == self.end {
+            None
+        } else {
+            unsafe {
+                let result = ptr::read(self.start);
+
+                self.start = if mem::size_of::<T>() == 0 {
+                    (self.start as usize + 1) as *const _
+                } else {
+                    self.start.offset(1)
+                };
+
+                Some(result)
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let elem_size = mem::size_of::<T>();
+        let len = (self.end as usize - self.start as usize) / elem_size.max(1);
+
+        (len, Some(len))
+    }
+}
+
+impl<T> DoubleEndedIterator for RawValIter<T> {
+    fn next_back(&mut self) -> Option<T> {
+        if self.start == self.end {
+            None
+        } else {
+            unsafe {
+                self.end = if mem::size_of::<T>() == 0 {
+                    (self.end as usize - 1) as *const _
+                } else {
+                    self.end.offset(-1)
+                };
+
+                Some(ptr::read(self.end))
+            }
+        }
+    }
+}
+
+/// Owns a heap allocation sized for `T` and knows how to grow or free it.
+/// Pulled out of `Vec` so that `Vec` and `IntoIter` can share the exact same
+/// allocate/grow/free logic instead of duplicating it.
+struct RawVec<T> {
+    ptr: Unique<T>,
+    cap: usize,
+}
+
+unsafe impl<T: Send> Send for RawVec<T> {}
+unsafe impl<T: Sync> Sync for RawVec<T> {}
+
+impl<T> RawVec<T> {
+    fn new() -> Self {
+        // `!0` is a sentinel capacity for ZSTs: `len == cap` never holds, so
+        // `grow` is never called and we never try to allocate zero bytes.
+        let cap = if mem::size_of::<T>() == 0 { !0 } else { 0 };
+
+        RawVec {
             ptr: Unique::dangling(),
-            len: 0,
-            cap: 0,
+            cap,
         }
     }
 
-    fn grow(&mut self) {
-        unsafe {
-            let elem_size = mem::size_of::<T>();
-            let align = mem::align_of::<T>();
+    /// Grows to fit at least `used_cap + needed_extra_capacity` elements,
+    /// doubling the existing capacity when that's already enough. A no-op
+    /// if `self.cap` already satisfies the request.
+    fn reserve(&mut self, used_cap: usize, needed_extra_capacity: usize) {
+        // ZSTs already have a `cap` of `!0`, i.e. room for any `len`.
+        if mem::size_of::<T>() == 0 {
+            return;
+        }
 
-            let (new_cap, ptr) = if self.cap == 0 {
-                let layout = Layout::from_size_align_unchecked(elem_size, align);
-                let ptr = alloc(layout);
+        let required_cap = used_cap
+            .checked_add(needed_extra_capacity)
+            .expect("capacity overflow");
 
-                (1, ptr)
-            } else {
-                let new_cap = self.cap * 2;
-                let old_num_bytes = self.cap * elem_size;
+        if self.cap >= required_cap {
+            return;
+        }
 
-                let layout = Layout::from_size_align_unchecked(old_num_bytes, align);
+        let new_cap = required_cap.max(self.cap * 2);
 
-                assert!(
-                    old_num_bytes <= (isize::MAX as usize) / 2,
-                    "The capacity has overflown!"
-                );
+        self.grow_to(new_cap);
+    }
+
+    /// Grows to fit exactly `used_cap + needed_extra_capacity` elements.
+    fn reserve_exact(&mut self, used_cap: usize, needed_extra_capacity: usize) {
+        if mem::size_of::<T>() == 0 {
+            return;
+        }
+
+        let new_cap = used_cap
+            .checked_add(needed_extra_capacity)
+            .expect("capacity overflow");
+
+        if new_cap > self.cap {
+            self.grow_to(new_cap);
+        }
+    }
 
-                let new_num_bytes = old_num_bytes * 2;
+    fn grow_to(&mut self, new_cap: usize) {
+        unsafe {
+            let elem_size = mem::size_of::<T>();
+            assert!(elem_size != 0, "capacity overflow");
 
-                let ptr = realloc(self.ptr.as_ptr() as *mut _, layout, new_num_bytes);
+            let new_layout = Layout::array::<T>(new_cap).unwrap();
+
+            assert!(
+                new_layout.size() <= isize::MAX as usize,
+                "allocation too large"
+            );
 
-                (new_cap, ptr)
+            let ptr = if self.cap == 0 {
+                alloc(new_layout)
+            } else {
+                let old_layout = Layout::array::<T>(self.cap).unwrap();
+
+                realloc(self.ptr.as_ptr() as *mut u8, old_layout, new_layout.size())
             };
 
             if ptr.is_null() {
-                process::abort();
+                handle_alloc_error(new_layout);
             }
 
             self.ptr = Unique::new_unchecked(ptr as *mut _);
             self.cap = new_cap;
         }
     }
+}
+
+impl<T> Drop for RawVec<T> {
+    fn drop(&mut self) {
+        if self.cap != 0 && mem::size_of::<T>() != 0 {
+            unsafe {
+                let layout = Layout::array::<T>(self.cap).unwrap();
+
+                dealloc(self.ptr.as_ptr() as *mut u8, layout);
+            }
+        }
+    }
+}
+
+impl<T> Vec<T> {
+    fn ptr(&self) -> *mut T {
+        self.buf.ptr.as_ptr()
+    }
+
+    fn cap(&self) -> usize {
+        self.buf.cap
+    }
+
+    pub fn new() -> Self {
+        Vec {
+            buf: RawVec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut vec = Vec::new();
+        vec.reserve_exact(capacity);
+        vec
+    }
+
+    /// Reserves capacity for at least `additional` more elements, growing
+    /// exponentially (like `push`'s implicit growth) if that's not already
+    /// satisfied.
+    pub fn reserve(&mut self, additional: usize) {
+        self.buf.reserve(self.len, additional);
+    }
+
+    /// Reserves capacity for exactly `additional` more elements.
+    pub fn reserve_exact(&mut self, additional: usize) {
+        self.buf.reserve_exact(self.len, additional);
+    }
+
+    fn grow(&mut self) {
+        self.reserve(1);
+    }
 
     pub fn push(&mut self, elem: T) {
-        if self.len == self.cap {
+        if self.len == self.cap() {
             self.grow();
         }
 
         unsafe {
-            ptr::write(self.ptr.as_ptr().offset(self.len as isize), elem);
+            ptr::write(self.ptr().offset(self.len as isize), elem);
         }
 
         self.len += 1;
@@ -85,26 +286,26 @@ impl<T> Vec<T> {
         } else {
             self.len -= 1;
 
-            unsafe { Some(ptr::read(self.ptr.as_ptr().offset(self.len as isize))) }
+            unsafe { Some(ptr::read(self.ptr().offset(self.len as isize))) }
         }
     }
 
     pub fn insert(&mut self, index: usize, elem: T) {
         assert!(index <= self.len, "Insertion index is out of bounds!");
 
-        if self.len == self.cap {
+        if self.len == self.cap() {
             self.grow();
         }
 
         unsafe {
             if index < self.len {
                 ptr::copy(
-                    self.ptr.as_ptr().offset(index as isize),
-                    self.ptr.as_ptr().offset(index as isize + 1),
+                    self.ptr().offset(index as isize),
+                    self.ptr().offset(index as isize + 1),
                     self.len - index,
                 );
             }
-            ptr::write(self.ptr.as_ptr().offset(index as isize), elem);
+            ptr::write(self.ptr().offset(index as isize), elem);
 
             self.len += 1;
         }
@@ -116,10 +317,10 @@ impl<T> Vec<T> {
         unsafe {
             self.len -= 1;
 
-            let result = ptr::read(self.ptr.as_ptr().offset(index as isize));
+            let result = ptr::read(self.ptr().offset(index as isize));
             ptr::copy(
-                self.ptr.as_ptr().offset(index as isize + 1),
-                self.ptr.as_ptr().offset(index as isize),
+                self.ptr().offset(index as isize + 1),
+                self.ptr().offset(index as isize),
                 self.len - index,
             );
 
@@ -128,22 +329,30 @@ impl<T> Vec<T> {
     }
 
     fn into_iter(self) -> IntoIter<T> {
-        let ptr = self.ptr;
-        let cap = self.cap;
-        let len = self.len;
+        unsafe {
+            let iter = RawValIter::new(&self);
 
-        mem::forget(self);
+            // Steal the buffer out from under `self`'s `Drop` impl; `IntoIter`
+            // takes over ownership (and freeing) of the allocation from here.
+            let buf = ptr::read(&self.buf);
 
+            mem::forget(self);
+
+            IntoIter { iter, _buf: buf }
+        }
+    }
+
+    pub fn drain(&mut self) -> Drain<'_, T> {
         unsafe {
-            IntoIter {
-                buf: ptr,
-                cap: cap,
-                start: ptr.as_ptr() as *const _,
-                end: if cap == 0 {
-                    ptr.as_ptr() as *const _
-                } else {
-                    ptr.as_ptr().offset(len as isize)
-                },
+            let iter = RawValIter::new(self);
+
+            // Zero the length up front so that a leaked/forgotten `Drain`
+            // can never cause the leftover elements to be dropped twice.
+            self.len = 0;
+
+            Drain {
+                iter,
+                vec: PhantomData,
             }
         }
     }
@@ -152,85 +361,249 @@ impl<T> Vec<T> {
 impl<T> Deref for Vec<T> {
     type Target = [T];
     fn deref(&self) -> &[T] {
-        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+        unsafe { std::slice::from_raw_parts(self.ptr(), self.len) }
     }
 }
 
 impl<T> DerefMut for Vec<T> {
     fn deref_mut(&mut self) -> &mut [T] {
-        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+        unsafe { std::slice::from_raw_parts_mut(self.ptr(), self.len) }
     }
 }
 
 impl<T> Drop for Vec<T> {
     fn drop(&mut self) {
-        if self.cap != 0 {
-            while let Some(_) = self.pop() {}
+        while let Some(_) = self.pop() {}
 
-            let elem_size = mem::size_of::<T>();
-            let align = mem::align_of::<T>();
-            let num_bytes = elem_size * self.cap;
+        // The allocation itself is freed by `RawVec`'s own `Drop` impl.
+    }
+}
 
-            unsafe {
-                let layout = Layout::from_size_align_unchecked(num_bytes, align);
+impl<T> IntoIterator for Vec<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
 
-                dealloc(self.ptr.as_ptr() as *mut _, layout);
-            }
-        }
+    fn into_iter(self) -> IntoIter<T> {
+        // Resolves to the inherent `Vec::into_iter` above: inherent methods
+        // are always preferred over trait methods of the same name.
+        self.into_iter()
     }
 }
 
-impl<T> Drop for IntoIter<T> {
-    fn drop(&mut self) {
-        if self.cap != 0 {
-            for _ in &mut *self {}
+impl<T> FromIterator<T> for Vec<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let iter = iter.into_iter();
+        let (lower, _) = iter.size_hint();
 
-            let elem_size = mem::size_of::<T>();
-            let align = mem::align_of::<T>();
-            let num_bytes = elem_size * self.cap;
+        let mut vec = Vec::with_capacity(lower);
 
-            unsafe {
-                let layout = Layout::from_size_align_unchecked(num_bytes, align);
+        for elem in iter {
+            vec.push(elem);
+        }
 
-                dealloc(self.buf.as_ptr() as *mut _, layout)
-            }
+        vec
+    }
+}
+
+impl<T> Extend<T> for Vec<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for elem in iter {
+            self.push(elem);
         }
     }
 }
 
+unsafe impl<T: Send> Send for IntoIter<T> {}
+unsafe impl<T: Sync> Sync for IntoIter<T> {}
+
+impl<T> Drop for IntoIter<T> {
+    fn drop(&mut self) {
+        for _ in &mut *self {}
+
+        // The allocation itself is freed by `RawVec`'s own `Drop` impl.
+    }
+}
+
 impl<T> Iterator for IntoIter<T> {
     type Item = T;
     fn next(&mut self) -> Option<T> {
-        if self.start == self.end {
-            None
-        } else {
-            unsafe {
-                let result = ptr::read(self.start);
-                self.start = self.start.offset(1);
-
-                Some(result)
-            }
-        }
+        self.iter.next()
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        let len = (self.end as usize - self.start as usize) / mem::size_of::<T>();
-
-        (len, Some(len))
+        self.iter.size_hint()
     }
 }
 
 impl<T> DoubleEndedIterator for IntoIter<T> {
     fn next_back(&mut self) -> Option<T> {
-        if self.start == self.end {
-            None
-        } else {
-            unsafe {
-                self.end = self.end.offset(-1);
-                let elem = ptr::read(self.end);
+        self.iter.next_back()
+    }
+}
+
+impl<'a, T> Iterator for Drain<'a, T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        self.iter.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, T> DoubleEndedIterator for Drain<'a, T> {
+    fn next_back(&mut self) -> Option<T> {
+        self.iter.next_back()
+    }
+}
+
+impl<'a, T> Drop for Drain<'a, T> {
+    fn drop(&mut self) {
+        for _ in &mut *self {}
+
+        // The vector's allocation is left untouched; only the vector's `len`
+        // was cleared by `Vec::drain`, so the buffer is still there to reuse.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Vec;
+
+    #[test]
+    fn zst_push_pop() {
+        let mut v: Vec<()> = Vec::new();
+
+        v.push(());
+        v.push(());
+        v.push(());
+
+        assert_eq!(v.len(), 3);
+        assert_eq!(v.pop(), Some(()));
+        assert_eq!(v.len(), 2);
+        assert_eq!(v.pop(), Some(()));
+        assert_eq!(v.pop(), Some(()));
+        assert_eq!(v.pop(), None);
+    }
+
+    #[test]
+    fn zst_into_iter_count() {
+        let mut v: Vec<()> = Vec::new();
+
+        for _ in 0..5 {
+            v.push(());
+        }
+
+        let count = v.into_iter().count();
+
+        assert_eq!(count, 5);
+    }
+
+    #[test]
+    fn drain_partial_then_reuse() {
+        let mut v: Vec<i32> = Vec::new();
+
+        v.push(1);
+        v.push(2);
+        v.push(3);
+
+        {
+            let mut drain = v.drain();
+
+            assert_eq!(drain.next(), Some(1));
+            assert_eq!(drain.next(), Some(2));
+        }
 
-                Some(elem)
+        assert_eq!(v.len(), 0);
+
+        v.push(4);
+        v.push(5);
+
+        assert_eq!(v.pop(), Some(5));
+        assert_eq!(v.pop(), Some(4));
+        assert_eq!(v.pop(), None);
+    }
+
+    #[test]
+    fn push_grows_capacity_geometrically() {
+        let mut v: Vec<i32> = Vec::new();
+        let mut reallocs = 0;
+        let mut last_cap = v.cap();
+
+        for i in 0..10_000 {
+            v.push(i);
+
+            if v.cap() != last_cap {
+                reallocs += 1;
+                last_cap = v.cap();
             }
         }
+
+        // Doubling from 0 takes ~log2(10_000) reallocations, not one per push.
+        assert!(
+            reallocs < 20,
+            "expected geometric growth, saw {} reallocations",
+            reallocs
+        );
+    }
+
+    #[test]
+    fn reserve_is_noop_when_capacity_already_suffices() {
+        let mut v: Vec<i32> = Vec::with_capacity(100);
+        v.push(0);
+
+        let cap_before = v.cap();
+        v.reserve(5);
+
+        assert_eq!(v.cap(), cap_before);
+    }
+
+    #[test]
+    fn vec_impl_macro() {
+        let list: Vec<i32> = vec_impl![1, 2, 3];
+        assert_eq!(&*list, [1, 2, 3]);
+
+        let repeated: Vec<i32> = vec_impl![7; 4];
+        assert_eq!(&*repeated, [7, 7, 7, 7]);
+
+        let empty: Vec<i32> = vec_impl![];
+        assert_eq!(empty.len(), 0);
+    }
+
+    #[test]
+    fn vec_impl_macro_repeat_evaluates_elem_once() {
+        use std::cell::Cell;
+
+        let counter = Cell::new(0);
+        let next = || {
+            let n = counter.get();
+            counter.set(n + 1);
+            n
+        };
+
+        let v: Vec<i32> = vec_impl![next(); 4];
+        assert_eq!(&*v, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn from_iterator_and_extend() {
+        let mut v: Vec<i32> = (0..5).collect();
+        assert_eq!(&*v, [0, 1, 2, 3, 4]);
+
+        v.extend(5..8);
+        assert_eq!(&*v, [0, 1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn into_iterator_for_loop() {
+        let v: Vec<i32> = vec_impl![1, 2, 3];
+        let mut sum = 0;
+
+        for elem in v {
+            sum += elem;
+        }
+
+        assert_eq!(sum, 6);
     }
 }